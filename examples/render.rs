@@ -10,6 +10,7 @@ extern crate png;
 #[macro_use]
 extern crate serde_derive;
 extern crate svg;
+extern crate toml;
 
 use bresenham::Bresenham;
 use docopt::Docopt;
@@ -21,21 +22,41 @@ use svg::node::element::{Group, Polyline};
 use svg::Document;
 
 use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
 use std::convert;
+use std::fs;
 use std::fs::File;
 use std::io::BufWriter;
 use std::ops::Deref;
 use std::rc::Rc;
 use std::str;
 
+/// Pixel size of a single slippy-map tile, matching the de-facto OSM/Web
+/// Mercator tiling standard.
+const TILE_SIZE: u32 = 256;
+
 const USAGE: &str = "
 Example renderer. Support PNG and SVG.
 
 Usage:
-  render <input> <output> [--width=<px>]
+  render <input> <output> [--width=<px>] [--bbox=<west,south,east,north>] [--tiles=<minz-maxz>] [--route=<route>] [--style=<path>]
 
 Options:
-  --width=<px>    canvas width [default: 4000]
+  --width=<px>                      canvas width [default: 4000]
+  --bbox=<west,south,east,north>    restrict rendering to this geographic
+                                     viewport (decimal degrees) instead of
+                                     the extent of every matching way
+  --tiles=<minz-maxz>                emit a z/x/y tile pyramid (256px tiles)
+                                     under <output> (extension removed)
+                                     instead of one canvas,
+                                     covering every zoom level in the range
+  --route=<from_lat,from_lon,to_lat,to_lon>
+                                     compute the shortest drivable path
+                                     between the two points and draw it on
+                                     top of the base map
+  --style=<path>                    TOML classificator overriding the
+                                     built-in style (see default_style.toml)
 ";
 
 #[derive(Debug, Deserialize)]
@@ -43,6 +64,10 @@ pub struct Args {
     arg_input: String,
     arg_output: std::path::PathBuf,
     flag_width: u32,
+    flag_bbox: Option<String>,
+    flag_tiles: Option<String>,
+    flag_route: Option<String>,
+    flag_style: Option<String>,
 }
 
 pub fn parse_args() -> Args {
@@ -51,6 +76,74 @@ pub fn parse_args() -> Args {
         .unwrap_or_else(|e| e.exit())
 }
 
+/// Parses a `--bbox=<west,south,east,north>` flag value into the same
+/// scaled-degree `GeoCoord` space the archive's nodes are converted to (see
+/// `GeoCoord::from` and `COORD_SCALE`), returning `(min, max)` corners.
+fn parse_bbox(s: &str) -> Result<(GeoCoord, GeoCoord), Error> {
+    let parts: Vec<f64> = s.split(',')
+        .map(|p| p.trim().parse::<f64>())
+        .collect::<Result<_, _>>()
+        .map_err(|e| format_err!("invalid --bbox: {}", e))?;
+    match parts.as_slice() {
+        [west, south, east, north] => {
+            if west >= east || south >= north {
+                bail!("invalid --bbox: west must be less than east and south less than north");
+            }
+            Ok((
+                GeoCoord {
+                    lat: *south,
+                    lon: *west,
+                },
+                GeoCoord {
+                    lat: *north,
+                    lon: *east,
+                },
+            ))
+        }
+        _ => bail!("invalid --bbox: expected west,south,east,north"),
+    }
+}
+
+/// Parses a `--tiles=<minz-maxz>` flag value into an inclusive zoom range.
+fn parse_tiles(s: &str) -> Result<(u32, u32), Error> {
+    let parts: Vec<&str> = s.split('-').collect();
+    match parts.as_slice() {
+        [minz, maxz] => {
+            let minz: u32 = minz.parse()
+                .map_err(|e| format_err!("invalid --tiles: {}", e))?;
+            let maxz: u32 = maxz.parse()
+                .map_err(|e| format_err!("invalid --tiles: {}", e))?;
+            if minz > maxz {
+                bail!("invalid --tiles: minz must not be greater than maxz");
+            }
+            Ok((minz, maxz))
+        }
+        _ => bail!("invalid --tiles: expected minz-maxz"),
+    }
+}
+
+/// Parses a `--route=<from_lat,from_lon,to_lat,to_lon>` flag value into the
+/// route's `(from, to)` endpoints.
+fn parse_route(s: &str) -> Result<(GeoCoord, GeoCoord), Error> {
+    let parts: Vec<f64> = s.split(',')
+        .map(|p| p.trim().parse::<f64>())
+        .collect::<Result<_, _>>()
+        .map_err(|e| format_err!("invalid --route: {}", e))?;
+    match parts.as_slice() {
+        [from_lat, from_lon, to_lat, to_lon] => Ok((
+            GeoCoord {
+                lat: *from_lat,
+                lon: *from_lon,
+            },
+            GeoCoord {
+                lat: *to_lat,
+                lon: *to_lon,
+            },
+        )),
+        _ => bail!("invalid --route: expected from_lat,from_lon,to_lat,to_lon"),
+    }
+}
+
 #[derive(Debug, Clone, Copy, Default, PartialEq, PartialOrd)]
 struct GeoCoord {
     lat: f64,
@@ -95,6 +188,10 @@ impl Color {
     fn new(r: u8, g: u8, b: u8, a: u8) -> Self {
         Self { r, g, b, a }
     }
+
+    fn to_hex(self) -> String {
+        format!("#{:02X}{:02X}{:02X}", self.r, self.g, self.b)
+    }
 }
 
 #[derive(Debug)]
@@ -120,6 +217,39 @@ impl Image {
         self.data[i + 2] = c.b;
         self.data[i + 3] = c.a;
     }
+
+    /// Sets the pixel at `(x, y)` to `c`, silently skipping coordinates that
+    /// fall outside the canvas instead of panicking.
+    fn set_clipped(&mut self, x: isize, y: isize, c: Color) {
+        if x >= 0 && y >= 0 && (x as u32) < self.w && (y as u32) < self.h {
+            self.set(x as u32, y as u32, c);
+        }
+    }
+
+    /// Stamps a filled disk of the given `radius` (in pixels) centered on
+    /// `(cx, cy)`, used to draw stroke widths wider than a single pixel.
+    fn stamp(&mut self, cx: isize, cy: isize, radius: isize, c: Color) {
+        if radius <= 0 {
+            self.set_clipped(cx, cy, c);
+            return;
+        }
+        let r2 = radius * radius;
+        for dy in -radius..=radius {
+            for dx in -radius..=radius {
+                if dx * dx + dy * dy <= r2 {
+                    self.set_clipped(cx + dx, cy + dy, c);
+                }
+            }
+        }
+    }
+
+    /// Draws a line from `from` to `to` using Bresenham's algorithm, stamping
+    /// a disk of `radius` pixels at every point to emulate stroke width.
+    fn draw_line(&mut self, from: (isize, isize), to: (isize, isize), radius: isize, c: Color) {
+        for (x, y) in Bresenham::new(from, to) {
+            self.stamp(x, y, radius, c);
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -153,8 +283,8 @@ impl MapTransform {
 
     fn transform_meters(&self, distance: u32) -> u32 {
         let start = haversine::Location {
-            latitude: self.min_x,
-            longitude: self.min_y,
+            latitude: self.min_y,
+            longitude: self.min_x,
         };
         let end = haversine::Location {
             latitude: self.map_h / self.height as f64 + self.min_y,
@@ -165,6 +295,40 @@ impl MapTransform {
     }
 }
 
+/// Converts a geographic coordinate into the Web Mercator tile it falls into
+/// at zoom level `z`, following the standard slippy-map tile scheme.
+fn lon_lat_to_tile(lon: f64, lat: f64, z: u32) -> (u32, u32) {
+    let n = 2f64.powi(z as i32);
+    let lat_rad = lat.to_radians();
+    let xtile = (lon + 180.) / 360. * n;
+    let ytile = (1. - (lat_rad.tan() + 1. / lat_rad.cos()).ln() / std::f64::consts::PI) / 2. * n;
+    (
+        xtile.max(0.).min(n - 1.) as u32,
+        ytile.max(0.).min(n - 1.) as u32,
+    )
+}
+
+/// The inverse of `lon_lat_to_tile`: the geographic bounds covered by tile
+/// `(x, y)` at zoom level `z`, as `(min, max)` corners.
+fn tile_bounds(x: u32, y: u32, z: u32) -> (GeoCoord, GeoCoord) {
+    let n = 2f64.powi(z as i32);
+    let tile_lon = |x: u32| x as f64 / n * 360. - 180.;
+    let tile_lat = |y: u32| {
+        let rad = std::f64::consts::PI * (1. - 2. * y as f64 / n);
+        rad.sinh().atan().to_degrees()
+    };
+    (
+        GeoCoord {
+            lat: tile_lat(y + 1),
+            lon: tile_lon(x),
+        },
+        GeoCoord {
+            lat: tile_lat(y),
+            lon: tile_lon(x + 1),
+        },
+    )
+}
+
 #[derive(Clone)]
 struct NodesIterator<'a> {
     nodes: flatdata::ArrayView<'a, osmflat::Node>,
@@ -183,27 +347,12 @@ impl<'a> NodesIterator<'a> {
         }
     }
 
-    fn from_way_type(archive: &'a osmflat::Osm, way_type: &WayType) -> Self {
-        let (next, end) = match way_type {
-            WayType::Park {
-                start_node_idx,
-                end_node_idx,
-            } => (start_node_idx, end_node_idx),
-            WayType::Road {
-                start_node_idx,
-                end_node_idx,
-            } => (start_node_idx, end_node_idx),
-            WayType::River {
-                start_node_idx,
-                end_node_idx,
-                ..
-            } => (start_node_idx, end_node_idx),
-        };
+    fn from_matched_way(archive: &'a osmflat::Osm, way: &MatchedWay) -> Self {
         Self {
             nodes: archive.nodes(),
             nodes_index: archive.nodes_index(),
-            next: *next as usize,
-            end: *end as usize,
+            next: way.start_node_idx as usize,
+            end: way.end_node_idx as usize,
         }
     }
 }
@@ -227,206 +376,796 @@ fn substring(strings: &str, start: u32) -> &str {
     &strings[start..start + end]
 }
 
-enum WayType {
-    Park {
-        start_node_idx: u32,
-        end_node_idx: u32,
-    },
-    Road {
-        start_node_idx: u32,
-        end_node_idx: u32,
-    },
-    River {
-        start_node_idx: u32,
-        end_node_idx: u32,
-        width: u32,
-    },
-}
-
-impl WayType {
-    fn width(&self) -> u32 {
-        match self {
-            WayType::Park { .. } => 1,
-            WayType::Road { .. } => 1,
-            WayType::River {
-                start_node_idx,
-                end_node_idx,
-                width,
-            } => *width,
+/// Unit a rule's `width` is expressed in: real-world meters (scaled through
+/// `MapTransform::transform_meters`) or fixed screen pixels.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum WidthUnit {
+    Meters,
+    Pixels,
+}
+
+impl Default for WidthUnit {
+    fn default() -> Self {
+        WidthUnit::Pixels
+    }
+}
+
+fn default_width() -> f64 {
+    1.
+}
+
+fn default_width_tag_scale() -> f64 {
+    1.
+}
+
+/// One entry in the style's classificator: rules are tried in order, first
+/// match wins.
+#[derive(Debug, Clone, Deserialize)]
+struct StyleRule {
+    key: String,
+    /// `None` matches any value of `key`.
+    #[serde(default)]
+    values: Option<Vec<String>>,
+    /// Drops the way instead of assigning it a layer.
+    #[serde(default)]
+    exclude: bool,
+    /// Draw bucket: rules sharing a `layer` name have their matched ways
+    /// drawn together, in the bucket's own z-order (see `z` below).
+    #[serde(default)]
+    layer: String,
+    /// Whether matched ways feed the `--route` road graph.
+    #[serde(default)]
+    routable: bool,
+    #[serde(default)]
+    stroke: Option<[u8; 4]>,
+    #[serde(default)]
+    fill: Option<[u8; 4]>,
+    /// In `width_unit`, used unless `width_tag` resolves one from the way's
+    /// own tags or `road_widths` supplies one for the way's class.
+    #[serde(default = "default_width")]
+    width: f64,
+    #[serde(default)]
+    width_unit: WidthUnit,
+    /// Tag keys, tried in order, carrying a per-way width override in
+    /// meters (e.g. a river's `width`/`maxwidth` tag).
+    #[serde(default)]
+    width_tag: Option<Vec<String>>,
+    /// Multiplier for the raw `width_tag` value: rivers use 20 since a
+    /// `width` tag is the true channel width, which renders as a hairline
+    /// at map scale otherwise.
+    #[serde(default = "default_width_tag_scale")]
+    width_tag_scale: f64,
+    /// Single-lane width in meters, keyed by the matched `key`'s value
+    /// (`"default"` as catch-all). Only set on a road rule.
+    #[serde(default)]
+    road_widths: Option<HashMap<String, f64>>,
+    /// Assumed lane count when a way has no `lanes` tag, keyed like
+    /// `road_widths`.
+    #[serde(default)]
+    default_lanes: Option<HashMap<String, u32>>,
+    /// Extra width per lane beyond the first, in meters.
+    #[serde(default = "default_lane_width_m")]
+    lane_width_m: f64,
+    /// Draw order among layers: lower first.
+    #[serde(default)]
+    z: i32,
+}
+
+fn default_lane_width_m() -> f64 {
+    3.
+}
+
+impl StyleRule {
+    fn stroke_color(&self) -> Color {
+        self.stroke
+            .map(|[r, g, b, a]| Color::new(r, g, b, a))
+            .unwrap_or_default()
+    }
+
+    fn fill_color(&self) -> Option<Color> {
+        self.fill.map(|[r, g, b, a]| Color::new(r, g, b, a))
+    }
+
+    fn width_px(&self, transform: &MapTransform, width_override_m: Option<u32>) -> u32 {
+        match width_override_m {
+            Some(m) => transform.transform_meters(m),
+            None => match self.width_unit {
+                WidthUnit::Meters => transform.transform_meters(self.width as u32),
+                WidthUnit::Pixels => self.width as u32,
+            },
+        }
+    }
+
+    /// `None` when this rule carries no `road_widths` table.
+    fn road_width_m(&self, class: &str, lanes: Option<u32>) -> Option<u32> {
+        let road_widths = self.road_widths.as_ref()?;
+        let base = road_widths
+            .get(class)
+            .or_else(|| road_widths.get("default"))?;
+        let lanes = lanes
+            .or_else(|| {
+                self.default_lanes.as_ref().and_then(|defaults| {
+                    defaults.get(class).or_else(|| defaults.get("default")).cloned()
+                })
+            })
+            .unwrap_or(1);
+        Some((base + self.lane_width_m * lanes.saturating_sub(1) as f64) as u32)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Style {
+    rules: Vec<StyleRule>,
+}
+
+impl Style {
+    /// Style baked into the binary, used when `--style` is not given.
+    fn default_style() -> Self {
+        toml::from_str(include_str!("default_style.toml")).expect("invalid built-in default style")
+    }
+
+    fn load(path: &std::path::Path) -> Result<Self, Error> {
+        let text = fs::read_to_string(path)?;
+        Ok(toml::from_str(&text)?)
+    }
+
+    /// Index of the first matching rule, plus a `width_tag`-derived width in
+    /// meters if any. `None` if no rule matches, or an `exclude` rule does.
+    fn classify(&self, tags: &[(&str, &str)]) -> Option<(usize, Option<u32>)> {
+        for (i, rule) in self.rules.iter().enumerate() {
+            let matched = tags.iter().any(|&(key, val)| {
+                key == rule.key && rule.values.as_ref().map_or(true, |vs| vs.iter().any(|v| v == val))
+            });
+            if !matched {
+                continue;
+            }
+            if rule.exclude {
+                return None;
+            }
+            let width_m = rule.width_tag.as_ref().and_then(|keys| {
+                keys.iter().find_map(|k| {
+                    tags.iter()
+                        .find(|&&(key, _)| key == k)
+                        .and_then(|&(_, val)| val.parse::<f64>().ok())
+                })
+            });
+            let width_m = width_m.map(|m| (m * rule.width_tag_scale) as u32);
+            return Some((i, width_m));
         }
+        None
     }
 }
 
+/// A way that matched a `StyleRule`: its node range, the matched rule, and
+/// any per-way width override resolved from its own tags.
+#[derive(Debug, Clone, Copy)]
+struct MatchedWay {
+    start_node_idx: u32,
+    end_node_idx: u32,
+    rule: usize,
+    width_override_m: Option<u32>,
+}
+
 fn way_filter(
     way: &osmflat::Way,
     next_way: &osmflat::Way,
     tags_index: &flatdata::ArrayView<osmflat::TagIndex>,
     tags: &flatdata::ArrayView<osmflat::Tag>,
     strings: &str,
-) -> Option<WayType> {
-    // Filter all ways that have less than 2 nodes.
+    style: &Style,
+) -> Option<MatchedWay> {
     let start_node_idx = way.ref_first_idx();
     let end_node_idx = next_way.ref_first_idx();
     if end_node_idx - start_node_idx < 2 {
         return None;
     }
 
-    // Filter all ways that do not have a highway tag. Also check for specific
-    // values.
     let start_tag_idx = way.tag_first_idx();
     let end_tag_idx = next_way.tag_first_idx();
-    for tag_idx in start_tag_idx..end_tag_idx {
-        let tag = tags.at(tags_index.at(tag_idx as usize).value() as usize);
-        let key = substring(strings, tag.key_idx());
-        if key == "highway" {
-            let val = substring(strings, tag.value_idx());
-            if val == "pedestrian" || val == "steps" || val == "footway" || val == "construction"
-                || val == "bic" || val == "cycleway" || val == "layby"
-                || val == "bridleway" || val == "path"
-            {
-                return None;
-            }
-            return Some(WayType::Road {
-                start_node_idx,
-                end_node_idx,
-            });
-        } else if key == "waterway" {
-            for tag_idx in start_tag_idx..end_tag_idx {
-                //let tag = tags.at(tags_index.at(tag_idx as usize).value() as usize);
-                let key = substring(strings, tag.key_idx());
-                if key == "width" || key == "maxwidth" {
-                    let val = substring(strings, tag.value_idx());
-                    let width: u32 = val.parse().ok()?;
-                    return Some(WayType::River {
-                        start_node_idx,
-                        end_node_idx,
-                        width,
-                    });
+    let way_tags: Vec<(&str, &str)> = (start_tag_idx..end_tag_idx)
+        .map(|tag_idx| {
+            let tag = tags.at(tags_index.at(tag_idx as usize).value() as usize);
+            (
+                substring(strings, tag.key_idx()),
+                substring(strings, tag.value_idx()),
+            )
+        })
+        .collect();
+
+    let (rule, mut width_override_m) = style.classify(&way_tags)?;
+    let matched_rule = &style.rules[rule];
+    if matched_rule.road_widths.is_some() {
+        if let Some(&(_, class)) = way_tags.iter().find(|&&(key, _)| key == matched_rule.key) {
+            let lanes = way_tags
+                .iter()
+                .find(|&&(key, _)| key == "lanes")
+                .and_then(|&(_, val)| val.parse().ok());
+            width_override_m = matched_rule.road_width_m(class, lanes).or(width_override_m);
+        }
+    }
+    Some(MatchedWay {
+        start_node_idx,
+        end_node_idx,
+        rule,
+        width_override_m,
+    })
+}
+
+/// Wraps an `f64` so it can be used as a `BinaryHeap`/`Ord` key for
+/// Dijkstra's priority queue. Distances here are always finite and
+/// non-negative, so `NaN` never arises in practice.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+struct MinFloat(f64);
+
+impl Eq for MinFloat {}
+
+impl Ord for MinFloat {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.partial_cmp(other).expect("NaN distance")
+    }
+}
+
+fn haversine_distance(from: GeoCoord, to: GeoCoord) -> f64 {
+    distance(
+        Location {
+            latitude: from.lat,
+            longitude: from.lon,
+        },
+        Location {
+            latitude: to.lat,
+            longitude: to.lon,
+        },
+        haversine::Units::Kilometers,
+    )
+}
+
+/// Builds a weighted graph of the drivable road network, keyed by node
+/// array index (the same index `NodesIterator` resolves through
+/// `nodes_index`), with edge weight equal to the haversine distance between
+/// consecutive nodes on a way. A way tagged `oneway=yes`/`-1` contributes a
+/// directed edge instead of two. Only ways matched by a `routable` style
+/// rule are considered.
+fn build_road_graph(archive: &osmflat::Osm, style: &Style) -> HashMap<usize, Vec<(usize, f64)>> {
+    let ways = archive.ways();
+    let nodes = archive.nodes();
+    let nodes_index = archive.nodes_index();
+    let tags_index = archive.tags_index();
+    let tags = archive.tags();
+    let strings = str::from_utf8(archive.stringtable())
+        .expect("stringtable contains invalid utf8 characters");
+
+    let mut graph: HashMap<usize, Vec<(usize, f64)>> = HashMap::new();
+
+    for (way, next_way) in ways.iter().zip(ways.iter().skip(1)) {
+        match way_filter(&*way, &*next_way, &tags_index, &tags, strings, style) {
+            Some(matched) if style.rules[matched.rule].routable => {}
+            _ => continue,
+        }
+
+        // -1 for oneway=-1 (backward only), 1 for oneway=yes (forward only),
+        // 0 for the default two-way street.
+        let mut oneway = 0i8;
+        let start_tag_idx = way.tag_first_idx();
+        let end_tag_idx = next_way.tag_first_idx();
+        for tag_idx in start_tag_idx..end_tag_idx {
+            let tag = tags.at(tags_index.at(tag_idx as usize).value() as usize);
+            if substring(strings, tag.key_idx()) == "oneway" {
+                match substring(strings, tag.value_idx()) {
+                    "yes" | "true" | "1" => oneway = 1,
+                    "-1" => oneway = -1,
+                    _ => {}
                 }
             }
-            return Some(WayType::River {
-                start_node_idx,
-                end_node_idx,
-                width: 1,
-            });
-        } else if key == "leisure" {
-            let val = substring(strings, tag.value_idx());
-            if val == "park" {
-                return Some(WayType::Park {
-                    start_node_idx,
-                    end_node_idx,
-                });
+        }
+
+        let node_indices: Vec<usize> = (way.ref_first_idx()..next_way.ref_first_idx())
+            .map(|idx| nodes_index.at(idx as usize).value() as usize)
+            .collect();
+
+        for (&a, &b) in node_indices.iter().zip(node_indices.iter().skip(1)) {
+            let weight = haversine_distance(GeoCoord::from(nodes.at(a)), GeoCoord::from(nodes.at(b)));
+            if oneway != -1 {
+                graph.entry(a).or_insert_with(Vec::new).push((b, weight));
+            }
+            if oneway != 1 {
+                graph.entry(b).or_insert_with(Vec::new).push((a, weight));
             }
         }
     }
 
-    None
+    graph
+}
+
+/// Snaps a geographic coordinate to the closest node that is actually part
+/// of the road graph, by brute-force minimum haversine distance.
+fn nearest_graph_node(
+    archive: &osmflat::Osm,
+    graph: &HashMap<usize, Vec<(usize, f64)>>,
+    coord: GeoCoord,
+) -> Option<usize> {
+    let nodes = archive.nodes();
+    // A oneway street's terminal node is only ever a target, never a source,
+    // so it never appears as a key; collect targets too or it can never be
+    // snapped to.
+    let mut candidates: HashSet<usize> = graph.keys().cloned().collect();
+    candidates.extend(graph.values().flatten().map(|&(node, _)| node));
+    candidates
+        .into_iter()
+        .min_by(|&a, &b| {
+            let da = haversine_distance(GeoCoord::from(nodes.at(a)), coord);
+            let db = haversine_distance(GeoCoord::from(nodes.at(b)), coord);
+            da.partial_cmp(&db).expect("NaN distance")
+        })
 }
 
-fn render(archive: &osmflat::Osm, output_path: &std::path::Path, width: u32) -> Result<(), Error> {
+/// Runs Dijkstra's algorithm over `graph` and returns the sequence of node
+/// indices from `start` to `end`, or `None` if they lie in disconnected
+/// components.
+fn shortest_path(
+    graph: &HashMap<usize, Vec<(usize, f64)>>,
+    start: usize,
+    end: usize,
+) -> Option<Vec<usize>> {
+    let mut dist: HashMap<usize, f64> = HashMap::new();
+    let mut prev: HashMap<usize, usize> = HashMap::new();
+    let mut heap = BinaryHeap::new();
+
+    dist.insert(start, 0.);
+    heap.push(Reverse((MinFloat(0.), start)));
+
+    while let Some(Reverse((MinFloat(cost), node))) = heap.pop() {
+        if node == end {
+            break;
+        }
+        if cost > *dist.get(&node).unwrap_or(&std::f64::INFINITY) {
+            continue;
+        }
+        if let Some(neighbors) = graph.get(&node) {
+            for &(next, weight) in neighbors {
+                let next_cost = cost + weight;
+                if next_cost < *dist.get(&next).unwrap_or(&std::f64::INFINITY) {
+                    dist.insert(next, next_cost);
+                    prev.insert(next, node);
+                    heap.push(Reverse((MinFloat(next_cost), next)));
+                }
+            }
+        }
+    }
+
+    if !dist.contains_key(&end) {
+        return None;
+    }
+
+    let mut path = vec![end];
+    while *path.last().unwrap() != start {
+        path.push(*prev.get(path.last().unwrap())?);
+    }
+    path.reverse();
+    Some(path)
+}
+
+/// Computes the shortest drivable path between `from` and `to`, returning
+/// the route as a sequence of geographic coordinates, or `None` when no
+/// route exists (e.g. the endpoints fall in disconnected components).
+fn compute_route(
+    archive: &osmflat::Osm,
+    style: &Style,
+    from: GeoCoord,
+    to: GeoCoord,
+) -> Option<Vec<GeoCoord>> {
+    let graph = build_road_graph(archive, style);
+    let start = nearest_graph_node(archive, &graph, from)?;
+    let end = nearest_graph_node(archive, &graph, to)?;
+    let path = shortest_path(&graph, start, end)?;
+
+    let nodes = archive.nodes();
+    Some(
+        path.into_iter()
+            .map(|idx| GeoCoord::from(nodes.at(idx)))
+            .collect(),
+    )
+}
+
+fn render(
+    archive: &osmflat::Osm,
+    style: &Style,
+    output_path: &std::path::Path,
+    width: u32,
+    bbox: Option<(GeoCoord, GeoCoord)>,
+    tiles: Option<(u32, u32)>,
+    route: Option<(GeoCoord, GeoCoord)>,
+) -> Result<(), Error> {
     let ways = archive.ways();
     let tags_index = archive.tags_index();
     let tags = archive.tags();
     let strings = str::from_utf8(archive.stringtable())
         .expect("stringtable contains invalid utf8 characters");
 
-    let roads = ways.iter()
+    let mut roads: Vec<MatchedWay> = ways.iter()
         .zip(ways.iter().skip(1))
-        .filter_map(|(way, next_way)| way_filter(&*way, &*next_way, &tags_index, &tags, strings));
+        .filter_map(|(way, next_way)| way_filter(&*way, &*next_way, &tags_index, &tags, strings, style))
+        .collect();
+
+    // When a viewport is given, drop ways that never enter it so we neither
+    // scan nor draw geometry outside the requested window.
+    if let Some((bbox_min, bbox_max)) = bbox {
+        roads.retain(|way| {
+            NodesIterator::from_matched_way(archive, way)
+                .map(GeoCoord::from)
+                .any(|coord| {
+                    coord.lat >= bbox_min.lat && coord.lat <= bbox_max.lat
+                        && coord.lon >= bbox_min.lon && coord.lon <= bbox_max.lon
+                })
+        });
+    }
 
-    // compute extent
-    let mut coords = roads
-        .clone()
-        .flat_map(|way_type| NodesIterator::from_way_type(archive, &way_type).map(GeoCoord::from));
+    if let Some((minz, maxz)) = tiles {
+        if route.is_some() {
+            bail!("--route is not supported together with --tiles");
+        }
+        return render_tiles(archive, style, &roads, minz, maxz, output_path);
+    }
 
-    let first_coord = coords.next().expect("no roads found");
-    let (min, max) = coords.fold((first_coord, first_coord), |(min, max), coord| {
-        (min.min(coord), max.max(coord))
-    });
+    let route = match route {
+        Some((from, to)) => match compute_route(archive, style, from, to) {
+            Some(path) => Some(path),
+            None => {
+                eprintln!("no route found between the given points, rendering map without it");
+                None
+            }
+        },
+        None => None,
+    };
+
+    // compute extent: either the caller-provided viewport, or the bounds of
+    // every matching way.
+    let (min, max) = match bbox {
+        Some((bbox_min, bbox_max)) => (bbox_min, bbox_max),
+        None => {
+            let mut coords = roads
+                .iter()
+                .flat_map(|way| NodesIterator::from_matched_way(archive, way).map(GeoCoord::from));
+            let first_coord = coords.next().expect("no roads found");
+            coords.fold((first_coord, first_coord), |(min, max), coord| {
+                (min.min(coord), max.max(coord))
+            })
+        }
+    };
 
     // compute ratio and height
     let ratio = 360. / 180. * (max.lat - min.lat) / (max.lon - min.lon);
     let height = (width as f64 * ratio) as u32;
 
+    let ext = match output_path.extension().and_then(|s| s.to_str()) {
+        Some(ext @ "png") | Some(ext @ "svg") => ext,
+        Some(_) => bail!("File extension not supported."),
+        None => bail!("Unable to guess format from file name (no extension)."),
+    };
+    render_canvas(
+        archive,
+        style,
+        &roads,
+        min,
+        max,
+        width,
+        height,
+        output_path,
+        ext,
+        route.as_ref().map(Vec::as_slice),
+    )
+}
+
+/// Buckets `roads` into the Web Mercator tile grid for every zoom level in
+/// `minz..=maxz` and renders each non-empty tile into `<dir>/z/x/y.<ext>`,
+/// where `<dir>` is `output_path` with its extension removed, reusing `render_canvas` with a 256px canvas and a per-tile `MapTransform`.
+fn render_tiles(
+    archive: &osmflat::Osm,
+    style: &Style,
+    roads: &[MatchedWay],
+    minz: u32,
+    maxz: u32,
+    output_path: &std::path::Path,
+) -> Result<(), Error> {
+    let ext = match output_path.extension().and_then(|s| s.to_str()) {
+        Some("svg") => "svg",
+        _ => "png",
+    };
+    let base_dir = output_path.with_extension("");
+
+    for z in minz..=maxz {
+        // Bucket every way into the tiles its node bounding box overlaps.
+        let mut tiles: HashMap<(u32, u32), Vec<MatchedWay>> = HashMap::new();
+        for &way in roads {
+            let mut coords = NodesIterator::from_matched_way(archive, &way).map(GeoCoord::from);
+            let first_coord = match coords.next() {
+                Some(coord) => coord,
+                None => continue,
+            };
+            let (min, max) = coords.fold((first_coord, first_coord), |(min, max), coord| {
+                (min.min(coord), max.max(coord))
+            });
+
+            let (x0, y0) = lon_lat_to_tile(min.lon, max.lat, z);
+            let (x1, y1) = lon_lat_to_tile(max.lon, min.lat, z);
+            for x in x0..=x1 {
+                for y in y0..=y1 {
+                    tiles.entry((x, y)).or_insert_with(Vec::new).push(way);
+                }
+            }
+        }
+
+        for ((x, y), tile_roads) in tiles {
+            let (min, max) = tile_bounds(x, y, z);
+            let tile_dir = base_dir.join(z.to_string()).join(x.to_string());
+            fs::create_dir_all(&tile_dir)?;
+            let tile_path = tile_dir.join(format!("{}.{}", y, ext));
+            render_canvas(
+                archive,
+                style,
+                &tile_roads,
+                min,
+                max,
+                TILE_SIZE,
+                TILE_SIZE,
+                &tile_path,
+                ext,
+                None,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Renders `roads` onto a single `width` x `height` canvas covering
+/// `min`..`max` and writes it to `output_path` in the given `ext` ("png" or
+/// "svg"), grouping ways by their style layer and drawing layers in
+/// ascending z-order. When `route` is given, it is drawn as a
+/// distinct-colored polyline on top of the base map.
+fn render_canvas(
+    archive: &osmflat::Osm,
+    style: &Style,
+    roads: &[MatchedWay],
+    min: GeoCoord,
+    max: GeoCoord,
+    width: u32,
+    height: u32,
+    output_path: &std::path::Path,
+    ext: &str,
+    route: Option<&[GeoCoord]>,
+) -> Result<(), Error> {
     // create world -> raster transformation
     let t = MapTransform::new(width - 1, height - 1, min, max);
 
-    // create paths
-    let paths = roads.map(|way_type| {
-        let raster_coords = NodesIterator::from_way_type(archive, &way_type)
-            .map(GeoCoord::from)
-            .map(|coord| t.transform(coord));
-        (raster_coords, way_type)
+    // bucket ways by their rule's layer, then visit layers in ascending
+    // z-order so e.g. roads (the highest z in the default style) land on top
+    // of parks and rivers; rules sharing a layer name share a bucket, each
+    // way keeping its own matched rule for styling
+    let mut by_layer: HashMap<&str, Vec<&MatchedWay>> = HashMap::new();
+    for way in roads {
+        let layer = style.rules[way.rule].layer.as_str();
+        by_layer.entry(layer).or_insert_with(Vec::new).push(way);
+    }
+    let mut layer_order: Vec<&str> = by_layer.keys().cloned().collect();
+    layer_order.sort_by_key(|layer| {
+        by_layer[layer].iter().map(|way| style.rules[way.rule].z).min().unwrap_or(0)
     });
 
+    let route_coords: Option<Vec<(isize, isize)>> = route
+        .map(|coords| coords.iter().map(|&coord| t.transform(coord)).collect());
+
     // detect whether we export svg or render to png
-    match output_path.extension() {
-        Some(os_str) => match os_str.to_str() {
-            Some("png") => {
-                let file = File::create(output_path)?;
-                let buf = BufWriter::new(file);
-                let mut image = Image::new(width, height);
-                let mut encoder = png::Encoder::new(buf, width, image.h);
-                encoder.set(png::ColorType::RGBA).set(png::BitDepth::Eight);
-                let mut writer = encoder.write_header()?;
-                for (nodes_iterator, width) in paths {
-                    //for (x, y) in Bresenham::new(from, to) {
-                    //    image.set(x as u32, y as u32, Color::new(0, 0, 0, 255));
-                    //}
+    match ext {
+        "png" => {
+            let file = File::create(output_path)?;
+            let buf = BufWriter::new(file);
+            let mut image = Image::new(width, height);
+            let mut encoder = png::Encoder::new(buf, width, image.h);
+            encoder.set(png::ColorType::RGBA).set(png::BitDepth::Eight);
+            let mut writer = encoder.write_header()?;
+            for layer in &layer_order {
+                for way in &by_layer[layer] {
+                    let rule = &style.rules[way.rule];
+                    let color = rule.stroke_color();
+                    let radius = (rule.width_px(&t, way.width_override_m) / 2) as isize;
+                    let coords: Vec<(isize, isize)> = NodesIterator::from_matched_way(archive, way)
+                        .map(GeoCoord::from)
+                        .map(|coord| t.transform(coord))
+                        .collect();
+                    for (&from, &to) in coords.iter().zip(coords.iter().skip(1)) {
+                        image.draw_line(from, to, radius, color);
+                    }
+                }
+            }
+            if let Some(route_coords) = &route_coords {
+                let route_color = Color::new(0xFF, 0x41, 0x36, 255);
+                for (&from, &to) in route_coords.iter().zip(route_coords.iter().skip(1)) {
+                    image.draw_line(from, to, 2, route_color);
                 }
-                writer.write_image_data(&image.data[..])?;
             }
-            Some("svg") => {
-                let mut document = Document::new().set("viewBox", (0, 0, width, height));
-                let mut road_group = Group::new().set("stroke", "#001F3F").set("fill", "none");
-                let mut park_group = Group::new()
-                    .set("stroke", "#3D9970")
-                    .set("fill", "#3D9970")
-                    .set("fill-opacity", 0.7);
-                let mut river_group = Group::new().set("stroke", "#0074D9").set("fill", "none");
-                for (mut nodes_iterator, way_type) in paths {
-                    //let first_node = nodes_iterator.next().unwrap();
-                    let v: Vec<String> = nodes_iterator
+            writer.write_image_data(&image.data[..])?;
+        }
+        "svg" => {
+            let mut document = Document::new().set("viewBox", (0, 0, width, height));
+            for layer in &layer_order {
+                let mut group = Group::new().set("id", layer.to_string());
+                for way in &by_layer[layer] {
+                    let rule = &style.rules[way.rule];
+                    let stroke = rule.stroke_color();
+                    let v: Vec<String> = NodesIterator::from_matched_way(archive, way)
+                        .map(GeoCoord::from)
+                        .map(|coord| t.transform(coord))
                         .map(|(x, y)| format!("{},{}", x, y))
                         .collect();
-                    match way_type {
-                        WayType::Road { .. } => {
-                            let mut polyline = Polyline::new().set("points", v.join(" "));
-                            road_group = road_group.add(polyline);
-                        }
-                        WayType::River {
-                            start_node_idx: _,
-                            end_node_idx: _,
-                            width,
-                        } => {
-                            let mut polyline = Polyline::new()
-                                .set("points", v.join(" "))
-                                .set("stroke-opacity", 0.8)
-                                .set("stroke-width", t.transform_meters(width * 20));
-                            river_group = river_group.add(polyline);
-                        }
-                        WayType::Park { .. } => {
-                            let mut polyline = Polyline::new().set("points", v.join(" "));
-                            park_group = park_group.add(polyline);
-                        }
+                    let mut polyline = Polyline::new()
+                        .set("points", v.join(" "))
+                        .set("stroke", stroke.to_hex())
+                        .set("stroke-opacity", stroke.a as f64 / 255.)
+                        .set(
+                            "fill",
+                            rule.fill_color().map(Color::to_hex).unwrap_or_else(|| "none".into()),
+                        )
+                        .set("stroke-width", rule.width_px(&t, way.width_override_m));
+                    if let Some(fill) = rule.fill_color() {
+                        polyline = polyline.set("fill-opacity", fill.a as f64 / 255.);
                     }
+                    group = group.add(polyline);
                 }
-                document = document.add(road_group).add(park_group).add(river_group);
-                svg::save(output_path, &document)?;
+                document = document.add(group);
             }
-            _ => bail!("File extension not supported."),
-        },
-        _ => bail!("Unable to guess format from file name (no extension)."),
+            if let Some(route_coords) = &route_coords {
+                let v: Vec<String> = route_coords
+                    .iter()
+                    .map(|(x, y)| format!("{},{}", x, y))
+                    .collect();
+                let route_group = Group::new()
+                    .set("stroke", "#FF4136")
+                    .set("stroke-width", 3)
+                    .set("fill", "none")
+                    .add(Polyline::new().set("points", v.join(" ")));
+                document = document.add(route_group);
+            }
+            svg::save(output_path, &document)?;
+        }
+        _ => bail!("File extension not supported."),
     }
     Ok(())
 }
 
 fn main() -> Result<(), Error> {
     let args = parse_args();
+    let bbox = args.flag_bbox
+        .as_ref()
+        .map(|s| parse_bbox(s))
+        .transpose()?;
+    let tiles = args.flag_tiles
+        .as_ref()
+        .map(|s| parse_tiles(s))
+        .transpose()?;
+    let route = args.flag_route
+        .as_ref()
+        .map(|s| parse_route(s))
+        .transpose()?;
+    let style = args.flag_style
+        .as_ref()
+        .map(|s| Style::load(std::path::Path::new(s)))
+        .transpose()?
+        .unwrap_or_else(Style::default_style);
     let storage = Rc::new(RefCell::new(FileResourceStorage::new(
         args.arg_input.into(),
     )));
     let archive = osmflat::Osm::open(storage)?;
-    render(&archive, &args.arg_output, args.flag_width)?;
+    render(
+        &archive,
+        &style,
+        &args.arg_output,
+        args.flag_width,
+        bbox,
+        tiles,
+        route,
+    )?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tile_tests {
+    use super::*;
+
+    #[test]
+    fn lon_lat_to_tile_roundtrips_through_tile_bounds() {
+        for &z in &[1u32, 5, 12, 18] {
+            let (x, y) = lon_lat_to_tile(2.3522, 48.8566, z); // Paris
+            let (min, max) = tile_bounds(x, y, z);
+            assert!(min.lon <= 2.3522 && 2.3522 <= max.lon);
+            assert!(min.lat <= 48.8566 && 48.8566 <= max.lat);
+        }
+    }
+
+    #[test]
+    fn lon_lat_to_tile_clamps_at_the_poles() {
+        // latitudes near +/-90 degrees are outside the Web Mercator range;
+        // the tile index should clamp rather than produce NaN/out-of-range
+        // values.
+        let (x, y) = lon_lat_to_tile(0., 89.9, 3);
+        assert!(x < 2u32.pow(3) && y < 2u32.pow(3));
+    }
+}
+
+#[cfg(test)]
+mod bbox_tests {
+    use super::*;
+
+    #[test]
+    fn parse_bbox_accepts_a_well_ordered_box() {
+        let (min, max) = parse_bbox("2.35,48.85,2.37,48.86").unwrap();
+        assert_eq!(min, GeoCoord { lat: 48.85, lon: 2.35 });
+        assert_eq!(max, GeoCoord { lat: 48.86, lon: 2.37 });
+    }
+
+    #[test]
+    fn parse_bbox_rejects_inverted_corners() {
+        assert!(parse_bbox("2.37,48.85,2.35,48.86").is_err()); // west >= east
+        assert!(parse_bbox("2.35,48.86,2.37,48.85").is_err()); // south >= north
+    }
+}
+
+#[cfg(test)]
+mod transform_tests {
+    use super::*;
+
+    #[test]
+    fn transform_meters_uses_lat_and_lon_in_the_right_slots() {
+        // A box around Paris where lat and lon are nowhere near equal, so a
+        // swapped start Location would throw the result off by orders of
+        // magnitude instead of a rounding error.
+        let min = GeoCoord {
+            lat: 48.85,
+            lon: 2.35,
+        };
+        let max = GeoCoord {
+            lat: 48.86,
+            lon: 2.37,
+        };
+        let t = MapTransform::new(1000, 500, min, max);
+        assert_eq!(t.transform_meters(10), 3_756_305);
+    }
+}
+
+#[cfg(test)]
+mod route_tests {
+    use super::*;
+
+    #[test]
+    fn shortest_path_picks_the_shorter_of_two_routes() {
+        let mut graph: HashMap<usize, Vec<(usize, f64)>> = HashMap::new();
+        graph.insert(0, vec![(1, 1.), (2, 1.)]);
+        graph.insert(1, vec![(3, 1.)]);
+        graph.insert(2, vec![(3, 10.)]);
+        graph.insert(3, vec![]);
+
+        assert_eq!(shortest_path(&graph, 0, 3), Some(vec![0, 1, 3]));
+    }
+
+    #[test]
+    fn shortest_path_returns_none_across_disconnected_components() {
+        let mut graph: HashMap<usize, Vec<(usize, f64)>> = HashMap::new();
+        graph.insert(0, vec![(1, 1.)]);
+        graph.insert(1, vec![]);
+        graph.insert(2, vec![(3, 1.)]);
+        graph.insert(3, vec![]);
+
+        assert_eq!(shortest_path(&graph, 0, 3), None);
+    }
+
+    #[test]
+    fn shortest_path_respects_a_directed_oneway_edge() {
+        // mirrors how build_road_graph only inserts a reverse edge when a
+        // way isn't tagged oneway
+        let mut graph: HashMap<usize, Vec<(usize, f64)>> = HashMap::new();
+        graph.insert(0, vec![(1, 1.)]);
+        graph.insert(1, vec![]);
+
+        assert_eq!(shortest_path(&graph, 0, 1), Some(vec![0, 1]));
+        assert_eq!(shortest_path(&graph, 1, 0), None);
+    }
+}